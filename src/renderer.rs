@@ -4,38 +4,332 @@
 
 use crate::{
     mesh::{Index, IndexFormat, MeshBuffers, Vertex},
-    scene::Scene,
+    scene::{Scene, SceneObject},
     uniform::{MaterialUniform, TransformUniform, ViewProjUniform},
 };
-use glam::Mat4;
+use glam::{Mat4, Vec3};
+use std::any::Any;
+use std::collections::HashMap;
+use wgpu::util::DeviceExt;
+
+/// Maximum number of shadow-casting lights a scene can have at once.
+///
+/// Backed by a fixed-size array in `LightsUniform` and a matching number of
+/// layers in the shadow pass' depth texture array.
+///
+/// Must match `MAX_LIGHTS` in `shaders/forward.frag`: there's no shared
+/// build-time constant between Rust and GLSL here, so changing one without
+/// the other silently desyncs `LightsUniform`'s layout from the shader's
+/// light array. The assert below only catches drift on this side.
+pub const MAX_LIGHTS: usize = 4;
+
+const _: () = assert!(
+    MAX_LIGHTS == 4,
+    "MAX_LIGHTS changed: update the matching `#define MAX_LIGHTS` in shaders/forward.frag too"
+);
+
+/// Near/far clip planes the debug depth pass' parameters start out with;
+/// `DebugPass::execute` overwrites them with the camera's own every frame.
+const NEAR_PLANE: f32 = 0.1;
+const FAR_PLANE: f32 = 100.0;
+
+/// User-facing renderer configuration.
+#[derive(Clone, Copy)]
+pub struct RendererConfig {
+    /// MSAA sample count (1, 2, 4 or 8) used by the forward pass.
+    pub sample_count: u32,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig { sample_count: 1 }
+    }
+}
+
+/// A view into a scene: supplies the matrices `Renderer::render` writes
+/// into the view-projection uniform every frame.
+pub trait Camera {
+    /// World-to-view matrix.
+    fn view(&self) -> Mat4;
+    /// View-to-clip matrix.
+    fn projection(&self) -> Mat4;
+    fn near(&self) -> f32;
+    fn far(&self) -> f32;
+    /// Whether `projection()` is an orthographic projection rather than a
+    /// perspective one. `DebugPass` needs this: depth isn't linear in clip
+    /// space the same way under both, so it linearizes differently.
+    fn is_orthographic(&self) -> bool;
+
+    /// Called by `Renderer::resize` with the render target's new
+    /// width / height aspect ratio.
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32);
+}
+
+/// A perspective `Camera` with a fixed vertical field of view.
+pub struct PerspectiveCamera {
+    pub view: Mat4,
+    pub fov_y: f32,
+    pub aspect_ratio: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera for PerspectiveCamera {
+    fn view(&self) -> Mat4 {
+        self.view
+    }
+
+    fn projection(&self) -> Mat4 {
+        Mat4::perspective_lh(self.fov_y, self.aspect_ratio, self.near, self.far)
+    }
+
+    fn near(&self) -> f32 {
+        self.near
+    }
+
+    fn far(&self) -> f32 {
+        self.far
+    }
+
+    fn is_orthographic(&self) -> bool {
+        false
+    }
+
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.aspect_ratio = aspect_ratio;
+    }
+}
+
+/// An orthographic `Camera` with a fixed vertical extent; its horizontal
+/// extent follows the aspect ratio so content doesn't stretch on resize.
+pub struct OrthographicCamera {
+    pub view: Mat4,
+    pub height: f32,
+    pub aspect_ratio: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera for OrthographicCamera {
+    fn view(&self) -> Mat4 {
+        self.view
+    }
+
+    fn projection(&self) -> Mat4 {
+        let half_height = self.height * 0.5;
+        let half_width = half_height * self.aspect_ratio;
+        Mat4::orthographic_lh(
+            -half_width,
+            half_width,
+            -half_height,
+            half_height,
+            self.near,
+            self.far,
+        )
+    }
+
+    fn near(&self) -> f32 {
+        self.near
+    }
+
+    fn far(&self) -> f32 {
+        self.far
+    }
+
+    fn is_orthographic(&self) -> bool {
+        true
+    }
+
+    fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.aspect_ratio = aspect_ratio;
+    }
+}
 
 /// The Renderer
 ///
 /// Manages GPU specific objects and performs the rendering
 pub struct Renderer {
     view_proj: ViewProj,
-    forward_pass: ForwardPass,
+    /// Ordered render graph. Each pass may read bind groups or texture
+    /// views published by an earlier pass out of the `SharedResources` it's
+    /// given, and may publish its own outputs for passes after it.
+    passes: Vec<Box<dyn RenderPass>>,
     transform_layout: wgpu::BindGroupLayout,
     material_layout: wgpu::BindGroupLayout,
+    /// Whether `DebugPass` was built. Skipped when MSAA is enabled, since
+    /// it can't read a multisampled depth texture; see `set_debug_view`.
+    debug_view_supported: bool,
+}
+
+/// A single stage of `Renderer`'s render graph.
+pub trait RenderPass {
+    /// Record this pass' commands against `encoder`. Implementations read
+    /// their inputs out of `shared` (published by earlier passes) and may
+    /// publish their own outputs into it for passes that run after them.
+    fn execute(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+        scene: &RendererScene,
+        camera: &dyn Camera,
+    );
+
+    /// Rebuild any resources sized to the render target (intermediate
+    /// textures, depth buffers, ...), publishing updated outputs into
+    /// `shared` for passes depending on them.
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+    );
+
+    /// Lets `Renderer` reach back into a concrete pass for pass-specific
+    /// controls (e.g. toggling `DebugPass`'s visibility).
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Bind groups and texture views one render pass hands to the passes that
+/// run after it, keyed by name. Rebuilt fresh for every `render`/`resize`
+/// call.
+#[derive(Default)]
+pub struct SharedResources {
+    bind_groups: HashMap<&'static str, wgpu::BindGroup>,
+    texture_views: HashMap<&'static str, wgpu::TextureView>,
+}
+
+impl SharedResources {
+    pub fn publish_bind_group(&mut self, name: &'static str, bind_group: wgpu::BindGroup) {
+        self.bind_groups.insert(name, bind_group);
+    }
+
+    pub fn bind_group(&self, name: &str) -> &wgpu::BindGroup {
+        self.bind_groups
+            .get(name)
+            .unwrap_or_else(|| panic!("render graph: no pass published bind group `{}`", name))
+    }
+
+    pub fn publish_texture_view(&mut self, name: &'static str, view: wgpu::TextureView) {
+        self.texture_views.insert(name, view);
+    }
+
+    pub fn texture_view(&self, name: &str) -> &wgpu::TextureView {
+        self.texture_views
+            .get(name)
+            .unwrap_or_else(|| panic!("render graph: no pass published texture view `{}`", name))
+    }
 }
 
 #[derive(Default)]
 pub struct RendererScene {
     pub objects: Vec<RendererSceneObject>,
-    pub view: Mat4,
+    pub lights: Vec<Light>,
+}
+
+/// A shadow-casting light in a `RendererScene`.
+pub struct Light {
+    pub view_proj: Mat4,
+    pub position: Vec3,
+    pub color: Vec3,
+}
+
+/// GPU layout for a single light, std140-padded for storage in `LightsUniform`.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightUniform {
+    view_proj: Mat4,
+    position: Vec3,
+    _pad0: f32,
+    color: Vec3,
+    _pad1: f32,
+}
+
+impl From<&Light> for LightUniform {
+    fn from(light: &Light) -> Self {
+        LightUniform {
+            view_proj: light.view_proj,
+            position: light.position,
+            _pad0: 0.0,
+            color: light.color,
+            _pad1: 0.0,
+        }
+    }
+}
+
+/// GPU layout for the parameters the debug depth pass needs to linearize a
+/// depth buffer: near/far planes, plus whether they came from an orthographic
+/// camera, since that needs a different linearization formula than a
+/// perspective one.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct DepthParamsUniform {
+    near: f32,
+    far: f32,
+    is_orthographic: u32,
+    _pad0: u32,
+}
+
+/// GPU layout for the full light array bound to the forward pass.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsUniform {
+    lights: [LightUniform; MAX_LIGHTS],
+    light_count: u32,
+    _pad: [u32; 3],
+}
+
+impl Default for LightsUniform {
+    fn default() -> Self {
+        LightsUniform {
+            lights: [LightUniform::zeroed(); MAX_LIGHTS],
+            light_count: 0,
+            _pad: [0; 3],
+        }
+    }
 }
 
 pub struct RendererSceneObject {
     pub meshes: Vec<MeshBuffers>,
     pub materials: Vec<wgpu::BindGroup>,
+    /// Model-space transform, used by the shadow pass.
     pub transform: wgpu::BindGroup,
+    /// Per-instance model matrices for the forward pass, uploaded once at
+    /// scene-creation time and bound as a second vertex buffer. Usually just
+    /// the object's own transform, but `create_scene_object_instanced` can
+    /// populate it with many, for a single instanced draw call.
+    pub instance_buffer: wgpu::Buffer,
+    pub instance_count: u32,
+}
+
+/// Per-instance vertex attributes: the four columns of a model matrix,
+/// appended after `Vertex::buffer_layout()`'s own attributes.
+const INSTANCE_ATTRIBUTES: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+    3 => Float32x4,
+    4 => Float32x4,
+    5 => Float32x4,
+    6 => Float32x4,
+];
+
+fn instance_buffer_layout<'a>() -> wgpu::VertexBufferLayout<'a> {
+    wgpu::VertexBufferLayout {
+        array_stride: std::mem::size_of::<Mat4>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Instance,
+        attributes: &INSTANCE_ATTRIBUTES,
+    }
+}
+
+fn create_instance_buffer(device: &wgpu::Device, instances: &[Mat4]) -> wgpu::Buffer {
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("instance_buffer"),
+        contents: bytemuck::cast_slice(instances),
+        usage: wgpu::BufferUsages::VERTEX,
+    })
 }
 
-#[allow(dead_code)]
 struct ViewProj {
-    data: ViewProjUniform,
     buffer: wgpu::Buffer,
-    layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
 }
 
@@ -43,19 +337,63 @@ struct ViewProj {
 struct ForwardPass {
     pipeline: wgpu::RenderPipeline,
     depth_texture_view: wgpu::TextureView,
+    msaa_texture_view: Option<wgpu::TextureView>,
+    // Kept around so `resize` can rebuild the pipeline for the new target
+    // size without the rest of the render graph having to resupply them.
+    view_proj_layout: wgpu::BindGroupLayout,
+    material_layout: wgpu::BindGroupLayout,
+    lights_layout: wgpu::BindGroupLayout,
+    sample_count: u32,
+}
+
+#[allow(dead_code)]
+struct ShadowPass {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    shadow_texture_view: wgpu::TextureView,
+    layer_views: Vec<wgpu::TextureView>,
+    lights_buffer: wgpu::Buffer,
+    lights_layout: wgpu::BindGroupLayout,
+    lights_bind_group: wgpu::BindGroup,
+}
+
+#[allow(dead_code)]
+struct DebugPass {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// When set, this pass overwrites the forward pass' shaded output with
+    /// a linearized visualization of its depth buffer.
+    enabled: bool,
 }
 
 impl Renderer {
-    pub fn new(device: &wgpu::Device, surface_conf: &wgpu::SurfaceConfiguration) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        adapter: &wgpu::Adapter,
+        target: &dyn RenderTarget,
+        config: RendererConfig,
+        camera: &dyn Camera,
+    ) -> Self {
+        let format = target.format();
+
+        // Validate the requested MSAA sample count against what the adapter
+        // actually supports for the target format.
+        let sample_flags = adapter.get_texture_format_features(format).flags;
+        assert!(
+            sample_flags.sample_count_supported(config.sample_count),
+            "sample count {} is not supported by this adapter for format {:?}",
+            config.sample_count,
+            format
+        );
+        let sample_count = config.sample_count;
+
         // Setup view projetion uniform
         let view_proj_data = ViewProjUniform {
-            proj: Mat4::perspective_lh(
-                (45.0f32).to_radians(),
-                surface_conf.width as f32 / surface_conf.height as f32,
-                0.1,
-                100.0,
-            ),
-            ..Default::default()
+            proj: camera.projection(),
+            view: camera.view(),
         };
         let view_proj_layout = ViewProjUniform::layout(&device);
         let view_proj_buffer = view_proj_data.create_buffer(&device);
@@ -72,107 +410,306 @@ impl Renderer {
         let transform_layout = TransformUniform::layout(&device);
         let material_layout = MaterialUniform::layout(&device);
 
+        // Setup shadow pass
+        let shadow_pass = ShadowPass::new(device, &transform_layout);
+
         // Setup forward pass
         let forward_pass = ForwardPass::new(
             device,
-            surface_conf,
+            target,
+            sample_count,
             &view_proj_layout,
-            &transform_layout,
             &material_layout,
+            &shadow_pass.lights_layout,
         );
 
+        // Setup the debug depth-view pass. It can only read a single-sample
+        // depth texture, so it's skipped entirely under MSAA rather than
+        // built against a depth view its bind group layout can't accept.
+        let debug_view_supported = sample_count == 1;
+        let debug_pass = debug_view_supported
+            .then(|| DebugPass::new(device, format, &forward_pass.depth_texture_view));
+
+        let mut passes: Vec<Box<dyn RenderPass>> =
+            vec![Box::new(shadow_pass), Box::new(forward_pass)];
+        if let Some(debug_pass) = debug_pass {
+            passes.push(Box::new(debug_pass));
+        }
+
         Renderer {
             view_proj: ViewProj {
-                data: view_proj_data,
                 buffer: view_proj_buffer,
-                layout: view_proj_layout,
                 bind_group: view_proj_bind_group,
             },
-            forward_pass,
+            passes,
             transform_layout,
             material_layout,
+            debug_view_supported,
         }
     }
 
-    pub fn resize(&mut self, device: &wgpu::Device, surface_conf: &wgpu::SurfaceConfiguration) {
-        // Recreate surface dependent passes
-        self.forward_pass = ForwardPass::new(
-            device,
-            surface_conf,
-            &self.view_proj.layout,
-            &self.transform_layout,
-            &self.material_layout,
+    /// Append a pass to the render graph, run after the built-in
+    /// shadow/forward/debug passes in the order pushed. This is how callers
+    /// compose extra stages (e.g. a post-process pass) without forking
+    /// `Renderer::render`.
+    ///
+    /// The new pass isn't sized yet; call `Renderer::resize` once after
+    /// pushing (or push before the first `resize` call) so it picks up the
+    /// current target's dimensions; `render` doesn't resize passes.
+    pub fn push_pass(&mut self, pass: Box<dyn RenderPass>) {
+        self.passes.push(pass);
+    }
+
+    /// Toggle between the forward pass' shaded output and a linearized
+    /// visualization of its depth buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `enabled` and the renderer was built with MSAA
+    /// (`RendererConfig::sample_count > 1`): `DebugPass` isn't built in that
+    /// case, since its bind group layout only declares a non-multisampled
+    /// depth binding and can't read an MSAA depth texture as-is. This is a
+    /// secondary guard against misuse — the pass simply doesn't exist to
+    /// misbind in the first place.
+    pub fn set_debug_view(&mut self, enabled: bool) {
+        assert!(
+            !enabled || self.debug_view_supported,
+            "debug view does not support a multisampled depth texture; disable MSAA to use set_debug_view"
         );
+        for pass in &mut self.passes {
+            if let Some(debug_pass) = pass.as_any_mut().downcast_mut::<DebugPass>() {
+                debug_pass.enabled = enabled;
+                return;
+            }
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        target: &dyn RenderTarget,
+        camera: &mut dyn Camera,
+    ) {
+        // Let the camera pick up the new aspect ratio itself instead of
+        // rebuilding its projection here.
+        let (width, height) = target.size();
+        camera.set_aspect_ratio(width as f32 / height as f32);
+
+        // Rebuild target-dependent passes in graph order, keyed on the
+        // target's own reported extent rather than a swapchain-specific
+        // config. A pass that depends on another pass' output (e.g. the
+        // debug pass reading the forward pass' depth texture) picks it back
+        // up from `shared`, same as during `render`.
+        let mut shared = SharedResources::default();
+        for pass in &mut self.passes {
+            pass.resize(device, target, &mut shared);
+        }
     }
 
     pub fn create_scene(&self, device: &wgpu::Device, scene: &Scene) -> RendererScene {
         let objects = scene
             .objects
             .iter()
-            .map(|object| {
-                let meshes = object
-                    .meshes
-                    .iter()
-                    .map(|m| m.create_buffers(device))
-                    .collect();
-                let materials = object
-                    .materials
-                    .iter()
-                    .map(|m| {
-                        MaterialUniform {
-                            albedo: m.unwrap_or_default().0,
-                        }
-                        .create_bind_group(device, &self.material_layout)
-                    })
-                    .collect();
-                let transform = TransformUniform {
-                    model: object.transform,
-                }
-                .create_bind_group(device, &self.transform_layout);
-                RendererSceneObject {
-                    meshes,
-                    materials,
-                    transform,
+            .map(|object| self.create_scene_object(device, object, &[object.transform]))
+            .collect();
+
+        RendererScene {
+            objects,
+            lights: Vec::new(),
+        }
+    }
+
+    /// Like the object created by `create_scene`, but drawn as
+    /// `transforms.len()` copies in a single instanced draw call instead of
+    /// one, for meshes repeated many times in a scene (e.g. foliage, crowds).
+    pub fn create_scene_object_instanced(
+        &self,
+        device: &wgpu::Device,
+        object: &SceneObject,
+        transforms: &[Mat4],
+    ) -> RendererSceneObject {
+        self.create_scene_object(device, object, transforms)
+    }
+
+    fn create_scene_object(
+        &self,
+        device: &wgpu::Device,
+        object: &SceneObject,
+        transforms: &[Mat4],
+    ) -> RendererSceneObject {
+        let meshes = object
+            .meshes
+            .iter()
+            .map(|m| m.create_buffers(device))
+            .collect();
+        let materials = object
+            .materials
+            .iter()
+            .map(|m| {
+                MaterialUniform {
+                    albedo: m.unwrap_or_default().0,
                 }
+                .create_bind_group(device, &self.material_layout)
             })
             .collect();
-
-        let view = scene.view;
-        RendererScene { objects, view }
+        let transform = TransformUniform {
+            model: object.transform,
+        }
+        .create_bind_group(device, &self.transform_layout);
+        let instance_buffer = create_instance_buffer(device, transforms);
+        RendererSceneObject {
+            meshes,
+            materials,
+            transform,
+            instance_buffer,
+            instance_count: transforms.len() as u32,
+        }
     }
 
     pub fn render(
         &self,
         encoder: &mut wgpu::CommandEncoder,
         queue: &wgpu::Queue,
-        view: &wgpu::TextureView,
+        target: &dyn RenderTarget,
         scene: &RendererScene,
+        camera: &dyn Camera,
     ) {
-        // Update view projection uniform
+        // Update view projection uniform from the supplied camera.
         let vp = &self.view_proj;
         queue.write_buffer(
             &vp.buffer,
             0,
             bytemuck::bytes_of(&ViewProjUniform {
-                view: scene.view,
-                ..vp.data
+                proj: camera.projection(),
+                view: camera.view(),
             }),
         );
 
-        // Make forward pass
-        self.forward_pass
-            .execute(encoder, view, &vp.bind_group, scene);
+        // Run the render graph in order. Each pass publishes its outputs
+        // into `shared` for later passes to pick up (e.g. the shadow pass'
+        // lights bind group, the forward pass' depth texture).
+        let mut shared = SharedResources::default();
+        shared.publish_bind_group("view_proj", vp.bind_group.clone());
+        for pass in &self.passes {
+            pass.execute(encoder, queue, target, &mut shared, scene, camera);
+        }
+    }
+}
+
+/// A render destination: exposes the color attachment view along with its
+/// format and pixel dimensions, so `Renderer` doesn't need to assume it's
+/// always drawing into the window's swapchain image.
+pub trait RenderTarget {
+    fn view(&self) -> &wgpu::TextureView;
+    fn format(&self) -> wgpu::TextureFormat;
+    fn size(&self) -> (u32, u32);
+}
+
+/// A `RenderTarget` backed by the window's swapchain image.
+pub struct SurfaceTarget<'a> {
+    pub view: &'a wgpu::TextureView,
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl<'a> RenderTarget for SurfaceTarget<'a> {
+    fn view(&self) -> &wgpu::TextureView {
+        self.view
+    }
+
+    fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl<'a> SurfaceTarget<'a> {
+    pub fn from_surface_conf(
+        view: &'a wgpu::TextureView,
+        surface_conf: &wgpu::SurfaceConfiguration,
+    ) -> Self {
+        SurfaceTarget {
+            view,
+            format: surface_conf.format,
+            width: surface_conf.width,
+            height: surface_conf.height,
+        }
+    }
+}
+
+/// An off-screen `RenderTarget`, for screenshots, thumbnails, or rendering
+/// into a texture consumed by a UI layer.
+pub struct TextureTarget {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TextureTarget {
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("render_target_texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        TextureTarget {
+            texture,
+            view,
+            format,
+            width,
+            height,
+        }
+    }
+}
+
+impl RenderTarget for TextureTarget {
+    fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
 }
 
 impl ForwardPass {
     pub fn new(
         device: &wgpu::Device,
-        surface_conf: &wgpu::SurfaceConfiguration,
+        target: &dyn RenderTarget,
+        sample_count: u32,
         view_proj_layout: &wgpu::BindGroupLayout,
-        transform_layout: &wgpu::BindGroupLayout,
         material_layout: &wgpu::BindGroupLayout,
+        lights_layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        let (width, height) = target.size();
+        let format = target.format();
+
         let vsrc = include_shader!("forward.vert");
         let fsrc = include_shader!("forward.frag");
         let vshader = device.create_shader_module(&vsrc);
@@ -181,22 +718,41 @@ impl ForwardPass {
         let depth_format = wgpu::TextureFormat::Depth32Float;
         let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
             size: wgpu::Extent3d {
-                width: surface_conf.width,
-                height: surface_conf.height,
+                width,
+                height,
                 depth_or_array_layers: 1,
             },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: depth_format,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
             label: None,
         });
         let depth_texture_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
+        // When multisampling, render into an intermediate MSAA color texture
+        // and resolve it into the target view at the end of the pass.
+        let msaa_texture_view = (sample_count > 1).then(|| {
+            let msaa_texture = device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                label: Some("forward_msaa_texture"),
+            });
+            msaa_texture.create_view(&wgpu::TextureViewDescriptor::default())
+        });
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
-            bind_group_layouts: &[view_proj_layout, transform_layout, material_layout],
+            bind_group_layouts: &[view_proj_layout, material_layout, lights_layout],
             push_constant_ranges: &[],
         });
         let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -205,12 +761,12 @@ impl ForwardPass {
             vertex: wgpu::VertexState {
                 module: &vshader,
                 entry_point: "main",
-                buffers: &[Vertex::buffer_layout()],
+                buffers: &[Vertex::buffer_layout(), instance_buffer_layout()],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &fshader,
                 entry_point: "main",
-                targets: &[surface_conf.format.into()],
+                targets: &[format.into()],
             }),
             primitive: wgpu::PrimitiveState::default(),
             depth_stencil: Some(wgpu::DepthStencilState {
@@ -220,27 +776,40 @@ impl ForwardPass {
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
-            multisample: wgpu::MultisampleState::default(),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
         });
 
         ForwardPass {
             pipeline,
             depth_texture_view,
+            msaa_texture_view,
+            view_proj_layout: view_proj_layout.clone(),
+            material_layout: material_layout.clone(),
+            lights_layout: lights_layout.clone(),
+            sample_count,
         }
     }
 
-    fn execute(
+    fn draw(
         &self,
         encoder: &mut wgpu::CommandEncoder,
         color_texture_view: &wgpu::TextureView,
         view_proj_bind_group: &wgpu::BindGroup,
+        lights_bind_group: &wgpu::BindGroup,
         scene: &RendererScene,
     ) {
+        let (view, resolve_target) = match &self.msaa_texture_view {
+            Some(msaa_view) => (msaa_view, Some(color_texture_view)),
+            None => (color_texture_view, None),
+        };
         let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[wgpu::RenderPassColorAttachment {
-                view: color_texture_view,
-                resolve_target: None,
+                view,
+                resolve_target,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                     store: true,
@@ -258,15 +827,469 @@ impl ForwardPass {
 
         rpass.set_pipeline(&self.pipeline);
         rpass.set_bind_group(0, &view_proj_bind_group, &[]);
+        rpass.set_bind_group(2, &lights_bind_group, &[]);
 
         for o in &scene.objects {
-            rpass.set_bind_group(1, &o.transform, &[]);
+            rpass.set_vertex_buffer(1, o.instance_buffer.slice(..));
             for (i, m) in o.meshes.iter().enumerate() {
-                rpass.set_bind_group(2, &o.materials[i], &[]);
+                rpass.set_bind_group(1, &o.materials[i], &[]);
                 rpass.set_vertex_buffer(0, m.vbuf.slice(..));
                 rpass.set_index_buffer(m.ibuf.slice(..), Index::format());
-                rpass.draw_indexed(0..m.nelems, 0, 0..1);
+                rpass.draw_indexed(0..m.nelems, 0, 0..o.instance_count);
+            }
+        }
+    }
+}
+
+impl RenderPass for ForwardPass {
+    fn execute(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        _queue: &wgpu::Queue,
+        target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+        scene: &RendererScene,
+        _camera: &dyn Camera,
+    ) {
+        self.draw(
+            encoder,
+            target.view(),
+            shared.bind_group("view_proj"),
+            shared.bind_group("lights"),
+            scene,
+        );
+        shared.publish_texture_view("forward_depth", self.depth_texture_view.clone());
+    }
+
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+    ) {
+        *self = ForwardPass::new(
+            device,
+            target,
+            self.sample_count,
+            &self.view_proj_layout,
+            &self.material_layout,
+            &self.lights_layout,
+        );
+        shared.publish_texture_view("forward_depth", self.depth_texture_view.clone());
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl ShadowPass {
+    const TEXTURE_SIZE: u32 = 2048;
+
+    pub fn new(device: &wgpu::Device, transform_layout: &wgpu::BindGroupLayout) -> Self {
+        let vsrc = include_shader!("shadow.vert");
+        let vshader = device.create_shader_module(&vsrc);
+
+        let shadow_format = wgpu::TextureFormat::Depth32Float;
+        let shadow_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: Self::TEXTURE_SIZE,
+                height: Self::TEXTURE_SIZE,
+                depth_or_array_layers: MAX_LIGHTS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: shadow_format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("shadow_texture"),
+        });
+        let shadow_texture_view = shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layer_views = (0..MAX_LIGHTS as u32)
+            .map(|layer| {
+                shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("lights_buffer"),
+            size: std::mem::size_of::<LightsUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let lights_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("lights_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+        });
+        let lights_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("lights_bind_group"),
+            layout: &lights_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: lights_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&shadow_texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&shadow_sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[transform_layout],
+            push_constant_ranges: &[wgpu::PushConstantRange {
+                stages: wgpu::ShaderStages::VERTEX,
+                range: 0..64,
+            }],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vshader,
+                entry_point: "main",
+                buffers: &[Vertex::buffer_layout()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: shadow_format,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        ShadowPass {
+            pipeline,
+            pipeline_layout,
+            shadow_texture_view,
+            layer_views,
+            lights_buffer,
+            lights_layout,
+            lights_bind_group,
+        }
+    }
+
+    fn update_lights(&self, queue: &wgpu::Queue, scene: &RendererScene) {
+        let mut data = LightsUniform::default();
+        for (i, light) in scene.lights.iter().take(MAX_LIGHTS).enumerate() {
+            data.lights[i] = LightUniform::from(light);
+        }
+        data.light_count = scene.lights.len().min(MAX_LIGHTS) as u32;
+        queue.write_buffer(&self.lights_buffer, 0, bytemuck::bytes_of(&data));
+    }
+
+    fn draw(&self, encoder: &mut wgpu::CommandEncoder, scene: &RendererScene) {
+        for (i, light) in scene.lights.iter().take(MAX_LIGHTS).enumerate() {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("shadow_pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layer_views[i],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            rpass.set_pipeline(&self.pipeline);
+            rpass.set_push_constants(
+                wgpu::ShaderStages::VERTEX,
+                0,
+                bytemuck::bytes_of(&light.view_proj),
+            );
+
+            for o in &scene.objects {
+                rpass.set_bind_group(0, &o.transform, &[]);
+                for m in &o.meshes {
+                    rpass.set_vertex_buffer(0, m.vbuf.slice(..));
+                    rpass.set_index_buffer(m.ibuf.slice(..), Index::format());
+                    rpass.draw_indexed(0..m.nelems, 0, 0..1);
+                }
             }
         }
     }
 }
+
+impl RenderPass for ShadowPass {
+    fn execute(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        _target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+        scene: &RendererScene,
+        _camera: &dyn Camera,
+    ) {
+        self.update_lights(queue, scene);
+        self.draw(encoder, scene);
+        shared.publish_bind_group("lights", self.lights_bind_group.clone());
+    }
+
+    fn resize(
+        &mut self,
+        _device: &wgpu::Device,
+        _target: &dyn RenderTarget,
+        _shared: &mut SharedResources,
+    ) {
+        // The shadow map array is a fixed size (`Self::TEXTURE_SIZE`),
+        // independent of the render target, so there's nothing to rebuild.
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl DebugPass {
+    /// `depth_texture_view` must be single-sample: the bind group layout
+    /// below declares a non-multisampled depth binding, so callers must not
+    /// construct this against an MSAA depth texture. `Renderer::new` only
+    /// builds this pass when `sample_count == 1` for exactly that reason.
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        depth_texture_view: &wgpu::TextureView,
+    ) -> Self {
+        let vsrc = include_shader!("fullscreen.vert");
+        let fsrc = include_shader!("debug_depth.frag");
+        let vshader = device.create_shader_module(&vsrc);
+        let fshader = device.create_shader_module(&fsrc);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("debug_depth_params"),
+            contents: bytemuck::bytes_of(&DepthParamsUniform {
+                near: NEAR_PLANE,
+                far: FAR_PLANE,
+                is_orthographic: 0,
+                _pad0: 0,
+            }),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("debug_depth_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("debug_depth_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vshader,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fshader,
+                entry_point: "main",
+                targets: &[format.into()],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &layout,
+            &sampler,
+            &params_buffer,
+            depth_texture_view,
+        );
+
+        DebugPass {
+            pipeline,
+            layout,
+            sampler,
+            params_buffer,
+            bind_group,
+            enabled: false,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        params_buffer: &wgpu::Buffer,
+        depth_texture_view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("debug_depth_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(depth_texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    fn draw(&self, encoder: &mut wgpu::CommandEncoder, color_texture_view: &wgpu::TextureView) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("debug_depth_pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: color_texture_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
+
+impl RenderPass for DebugPass {
+    fn execute(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        target: &dyn RenderTarget,
+        _shared: &mut SharedResources,
+        _scene: &RendererScene,
+        camera: &dyn Camera,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        // Linearize against the camera actually in use, not a fixed guess,
+        // so the visualization stays correct if near/far ever diverge from
+        // NEAR_PLANE/FAR_PLANE, and stays meaningful under an orthographic
+        // camera, whose depth is linear in clip space already.
+        queue.write_buffer(
+            &self.params_buffer,
+            0,
+            bytemuck::bytes_of(&DepthParamsUniform {
+                near: camera.near(),
+                far: camera.far(),
+                is_orthographic: camera.is_orthographic() as u32,
+                _pad0: 0,
+            }),
+        );
+        self.draw(encoder, target.view());
+    }
+
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        _target: &dyn RenderTarget,
+        shared: &mut SharedResources,
+    ) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.layout,
+            &self.sampler,
+            &self.params_buffer,
+            shared.texture_view("forward_depth"),
+        );
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}